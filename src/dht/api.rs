@@ -0,0 +1,100 @@
+//! Optional read-only HTTP/JSON API over the crawl: known info_hashes and their peers, routing
+//! table occupancy, and on-demand lookups.
+
+use dht::Dht;
+use proto::NodeID;
+
+use std::net::{SocketAddr, SocketAddrV4};
+
+use tokio::prelude::*;
+use warp::{self, Filter};
+
+#[derive(Serialize)]
+struct TorrentSummary {
+    info_hash: String,
+    peers: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    routing_table_size: usize,
+    bucket_fill: Vec<usize>,
+}
+
+impl Dht {
+    /// Serves the HTTP/JSON API on `bind_addr` until the returned future is dropped.
+    ///
+    /// * `GET /torrents` -- every info_hash in the torrent store (announced to us or cached from
+    ///   a `get_peers` lookup), with its known peers.
+    /// * `GET /torrents/:info_hash` -- peers for a specific info_hash; runs a `get_peers`
+    ///   lookup on demand if we don't already have any cached.
+    /// * `GET /stats` -- routing table size and per-bucket occupancy.
+    pub fn serve_api(&self, bind_addr: SocketAddr) -> impl Future<Item = (), Error = ()> {
+        let torrents_route = {
+            let dht = self.clone();
+
+            warp::path("torrents")
+                .and(warp::path::end())
+                .map(move || warp::reply::json(&dht.torrent_summaries()))
+        };
+
+        let torrent_peers_route = {
+            let dht = self.clone();
+
+            warp::path("torrents")
+                .and(warp::path::param::<String>())
+                .and(warp::path::end())
+                .and_then(move |info_hash: String| {
+                    let peers: Box<Future<Item = Vec<SocketAddrV4>, Error = warp::Rejection> + Send> =
+                        match info_hash.parse::<NodeID>() {
+                            Ok(info_hash) => Box::new(
+                                dht.get_peers(info_hash)
+                                    .map_err(|_| warp::reject::server_error()),
+                            ),
+                            Err(_) => Box::new(future::err(warp::reject::not_found())),
+                        };
+
+                    peers
+                }).map(|peers: Vec<SocketAddrV4>| {
+                    warp::reply::json(&peers.iter().map(ToString::to_string).collect::<Vec<_>>())
+                })
+        };
+
+        let stats_route = {
+            let dht = self.clone();
+
+            warp::path("stats")
+                .and(warp::path::end())
+                .map(move || warp::reply::json(&dht.stats()))
+        };
+
+        let routes = warp::get2().and(torrents_route.or(torrent_peers_route).or(stats_route));
+
+        warp::serve(routes).bind(bind_addr)
+    }
+
+    fn torrent_summaries(&self) -> Vec<TorrentSummary> {
+        self.torrents
+            .lock()
+            .map(|torrents| {
+                torrents
+                    .iter()
+                    .map(|(info_hash, peers)| TorrentSummary {
+                        info_hash: info_hash.to_string(),
+                        peers: peers.iter().map(ToString::to_string).collect(),
+                    }).collect()
+            }).unwrap_or_else(|_| Vec::new())
+    }
+
+    fn stats(&self) -> Stats {
+        self.routing_table
+            .lock()
+            .map(|routing_table| Stats {
+                routing_table_size: routing_table.len(),
+                bucket_fill: routing_table.bucket_fill(),
+            }).unwrap_or_else(|_| Stats {
+                routing_table_size: 0,
+                bucket_fill: Vec::new(),
+            })
+    }
+}
@@ -65,8 +65,10 @@ impl Dht {
             },
         };
 
+        // BEP 42: tell the querying node its own external address, so it can derive a
+        // security-extension-compliant node ID for itself.
         Message {
-            ip: None,
+            ip: Some(Addr::from(from)),
             transaction_id: request.transaction_id,
             version: None,
             message_type,
@@ -78,9 +80,7 @@ impl Dht {
         let mut routing_table = self.routing_table.lock()?;
         record_request(&mut routing_table, id, from, read_only)?;
 
-        Ok(Response::OnlyId {
-            id: self.id.clone(),
-        })
+        Ok(Response::OnlyId { id: self.id() })
     }
 
     fn handle_find_node(
@@ -99,7 +99,7 @@ impl Dht {
         };
 
         Ok(Response::NextHop {
-            id: self.id.clone(),
+            id: self.id(),
             token: None,
             nodes,
         })
@@ -122,7 +122,7 @@ impl Dht {
 
         if let Some(peers) = torrent {
             Ok(Response::GetPeers {
-                id: self.id.clone(),
+                id: self.id(),
                 token,
                 peers: peers.iter().map(|peer| Addr::from(peer.clone())).collect(),
             })
@@ -130,7 +130,7 @@ impl Dht {
             let nodes = routing_table.find_nodes(&info_hash);
 
             Ok(Response::NextHop {
-                id: self.id.clone(),
+                id: self.id(),
                 token,
                 nodes,
             })
@@ -174,9 +174,7 @@ impl Dht {
             .or_insert_with(Vec::new)
             .push(addr);
 
-        Ok(Response::OnlyId {
-            id: self.id.clone(),
-        })
+        Ok(Response::OnlyId { id: self.id() })
     }
 }
 
@@ -186,7 +184,7 @@ fn record_request<T: DerefMut<Target = RoutingTable>>(
     from: SocketAddrV4,
     read_only: bool,
 ) -> Result<()> {
-    if !read_only {
+    if !read_only && super::is_node_id_trustworthy(&id, from.ip()) {
         routing_table
             .deref_mut()
             .get_or_add(id, from)
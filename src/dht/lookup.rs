@@ -0,0 +1,374 @@
+//! Iterative Kademlia node lookup shared by `get_peers` and the self-lookup bootstrap step.
+
+use addr::AsV4Address;
+use dht::Dht;
+use errors::{Error, Result};
+use proto::{Addr, NodeID};
+use routing::Node;
+
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+
+use tokio::prelude::*;
+
+/// Number of nodes queried concurrently during a single lookup round.
+const ALPHA: usize = 3;
+
+/// Number of closest nodes a lookup converges on, matching the routing table's bucket size.
+const LOOKUP_K: usize = 8;
+
+/// Upper bound on the number of nodes a single lookup will query, so a lookup against a
+/// pathological or just very large network can't run forever.
+const MAX_QUERIED: usize = 256;
+
+/// Outcome of a completed iterative lookup.
+pub(crate) struct LookupResult {
+    /// The closest responded nodes, ascending by distance to the lookup target.
+    pub nodes: Vec<Node>,
+    /// Peers announced under the lookup target, populated when the lookup ran in `get_peers`
+    /// mode and a queried node had peers for it.
+    pub peers: Vec<Addr>,
+    /// The `get_peers` token returned by each node that answered, keyed by that node's address
+    /// so `announce` can hand the right token back to the right node.
+    pub tokens: HashMap<SocketAddrV4, Vec<u8>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum QueryState {
+    Unqueried,
+    Responded,
+    Failed,
+}
+
+struct ShortlistEntry {
+    node: Node,
+    state: QueryState,
+}
+
+/// Per-round state threaded through the `loop_fn` driving the lookup.
+struct LookupState {
+    target: NodeID,
+    get_peers: bool,
+    shortlist: Vec<ShortlistEntry>,
+    queried: usize,
+    peers: Vec<Addr>,
+    tokens: HashMap<SocketAddrV4, Vec<u8>>,
+    converged_on: Option<Vec<NodeID>>,
+}
+
+impl LookupState {
+    fn insert(&mut self, node: Node) {
+        if self.shortlist.iter().any(|entry| entry.node.id() == node.id()) {
+            return;
+        }
+
+        let target = self.target.clone();
+        let position = self
+            .shortlist
+            .iter()
+            .position(|entry| target.distance(entry.node.id()) > target.distance(node.id()))
+            .unwrap_or_else(|| self.shortlist.len());
+
+        self.shortlist.insert(
+            position,
+            ShortlistEntry {
+                node,
+                state: QueryState::Unqueried,
+            },
+        );
+    }
+
+    fn mark(&mut self, id: &NodeID, new_state: QueryState) {
+        if let Some(entry) = self.shortlist.iter_mut().find(|entry| entry.node.id() == id) {
+            entry.state = new_state;
+        }
+    }
+
+    fn closest_responded(&self) -> Vec<NodeID> {
+        self.shortlist
+            .iter()
+            .filter(|entry| entry.state == QueryState::Responded)
+            .take(LOOKUP_K)
+            .map(|entry| entry.node.id().clone())
+            .collect()
+    }
+
+    fn into_result(self) -> LookupResult {
+        LookupResult {
+            nodes: self
+                .shortlist
+                .into_iter()
+                .filter(|entry| entry.state == QueryState::Responded)
+                .take(LOOKUP_K)
+                .map(|entry| entry.node)
+                .collect(),
+            peers: self.peers,
+            tokens: self.tokens,
+        }
+    }
+}
+
+/// What a single queried node handed back, normalized across `find_node` and `get_peers`.
+enum RoundOutcome {
+    NextHop {
+        id: NodeID,
+        nodes: Vec<Node>,
+        external_addr: Option<SocketAddrV4>,
+    },
+    Peers {
+        id: NodeID,
+        token: Option<Vec<u8>>,
+        nodes: Vec<Node>,
+        peers: Vec<Addr>,
+        external_addr: Option<SocketAddrV4>,
+    },
+    Failed { id: NodeID },
+}
+
+impl Dht {
+    /// Runs an iterative Kademlia lookup for `target`.
+    ///
+    /// Seeds a shortlist with the closest nodes already known to the routing table, sorted
+    /// ascending by XOR distance to `target`. Each round fires `find_node` (or `get_peers`,
+    /// when `get_peers` is `true`) at the `ALPHA` closest unqueried nodes concurrently, merges
+    /// any nodes they return back into the shortlist, and stops once the closest `LOOKUP_K`
+    /// responded nodes are unchanged from the previous round or there is nothing left to query.
+    pub(crate) fn lookup(
+        &self,
+        target: NodeID,
+        get_peers: bool,
+    ) -> impl Future<Item = LookupResult, Error = Error> {
+        let send_transport = self.send_transport.clone();
+        let self_id = self.id();
+        let dht_for_ip = self.clone();
+
+        let seeds = self
+            .routing_table
+            .lock()
+            .map(|routing_table| routing_table.find_nodes(&target))
+            .unwrap_or_else(|_| Vec::new());
+
+        let mut state = LookupState {
+            target,
+            get_peers,
+            shortlist: Vec::new(),
+            queried: 0,
+            peers: Vec::new(),
+            tokens: HashMap::new(),
+            converged_on: None,
+        };
+
+        for node in seeds {
+            state.insert(node);
+        }
+
+        future::loop_fn(state, move |mut state| {
+            let budget = ALPHA.min(MAX_QUERIED.saturating_sub(state.queried));
+            let to_query: Vec<Node> = state
+                .shortlist
+                .iter()
+                .filter(|entry| entry.state == QueryState::Unqueried)
+                .take(budget)
+                .map(|entry| entry.node.clone())
+                .collect();
+
+            if to_query.is_empty() || state.queried >= MAX_QUERIED {
+                return Box::new(future::ok(future::Loop::Break(state.into_result())))
+                    as Box<Future<Item = _, Error = Error> + Send>;
+            }
+
+            state.queried += to_query.len();
+
+            let target = state.target.clone();
+            let get_peers = state.get_peers;
+
+            let queries = to_query.into_iter().map(move |node| {
+                let address = node.address();
+                let id = node.id().clone();
+                let failed_id = id.clone();
+
+                if get_peers {
+                    let query = send_transport
+                        .get_peers(self_id.clone(), target.clone(), address)
+                        .map(move |(token, nodes, peers, external_addr)| RoundOutcome::Peers {
+                            id: id.clone(),
+                            token,
+                            nodes,
+                            peers,
+                            external_addr,
+                        }).or_else(move |_| future::ok(RoundOutcome::Failed { id: failed_id }));
+
+                    Box::new(query) as Box<Future<Item = RoundOutcome, Error = Error> + Send>
+                } else {
+                    let query = send_transport
+                        .find_node(self_id.clone(), target.clone(), address)
+                        .map(move |(nodes, external_addr)| RoundOutcome::NextHop {
+                            id: id.clone(),
+                            nodes,
+                            external_addr,
+                        }).or_else(move |_| future::ok(RoundOutcome::Failed { id: failed_id }));
+
+                    Box::new(query) as Box<Future<Item = RoundOutcome, Error = Error> + Send>
+                }
+            });
+
+            let dht_for_ip = dht_for_ip.clone();
+
+            let round = future::join_all(queries).map(move |outcomes| {
+                for outcome in outcomes {
+                    match outcome {
+                        RoundOutcome::NextHop {
+                            id,
+                            nodes,
+                            external_addr,
+                        } => {
+                            state.mark(&id, QueryState::Responded);
+
+                            if let Some(addr) = external_addr {
+                                let _ = dht_for_ip.observe_external_ip(*addr.ip());
+                            }
+
+                            for node in nodes {
+                                state.insert(node);
+                            }
+                        }
+                        RoundOutcome::Peers {
+                            id,
+                            token,
+                            nodes,
+                            peers,
+                            external_addr,
+                        } => {
+                            state.mark(&id, QueryState::Responded);
+
+                            if let Some(addr) = external_addr {
+                                let _ = dht_for_ip.observe_external_ip(*addr.ip());
+                            }
+
+                            if let Some(token) = token {
+                                let stored_addr = state
+                                    .shortlist
+                                    .iter()
+                                    .find(|entry| entry.node.id() == &id)
+                                    .and_then(|entry| entry.node.address().into_v4().ok());
+
+                                if let Some(addr) = stored_addr {
+                                    state.tokens.insert(addr, token);
+                                }
+                            }
+
+                            for node in nodes {
+                                state.insert(node);
+                            }
+
+                            for peer in peers {
+                                if !state.peers.contains(&peer) {
+                                    state.peers.push(peer);
+                                }
+                            }
+                        }
+                        RoundOutcome::Failed { id } => {
+                            state.mark(&id, QueryState::Failed);
+                        }
+                    }
+                }
+
+                let closest = state.closest_responded();
+                let converged = state.converged_on.as_ref() == Some(&closest);
+                state.converged_on = Some(closest);
+
+                if converged {
+                    future::Loop::Break(state.into_result())
+                } else {
+                    future::Loop::Continue(state)
+                }
+            });
+
+            Box::new(round) as Box<Future<Item = _, Error = Error> + Send>
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn node_at(id: NodeID) -> Node {
+        Node::new(id, SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 6881).into())
+    }
+
+    #[test]
+    fn shortlist_stays_sorted_by_distance_to_target() {
+        let target = NodeID::random();
+        let mut state = LookupState {
+            target: target.clone(),
+            get_peers: false,
+            shortlist: Vec::new(),
+            queried: 0,
+            peers: Vec::new(),
+            tokens: HashMap::new(),
+            converged_on: None,
+        };
+
+        for _ in 0..16 {
+            state.insert(node_at(NodeID::random()));
+        }
+
+        let distances: Vec<_> = state
+            .shortlist
+            .iter()
+            .map(|entry| target.distance(entry.node.id()))
+            .collect();
+
+        let mut sorted = distances.clone();
+        sorted.sort();
+
+        assert_eq!(distances, sorted);
+    }
+
+    /// Mirrors what the `loop_fn` body in `Dht::lookup` does at the end of each round: recompute
+    /// `closest_responded`, compare it against what converged last round, then stash it.
+    fn run_round(state: &mut LookupState) -> bool {
+        let closest = state.closest_responded();
+        let converged = state.converged_on.as_ref() == Some(&closest);
+        state.converged_on = Some(closest);
+        converged
+    }
+
+    #[test]
+    fn convergence_requires_an_unchanged_closest_responded_set_across_a_round() {
+        let target = NodeID::random();
+        let mut state = LookupState {
+            target: target.clone(),
+            get_peers: false,
+            shortlist: Vec::new(),
+            queried: 0,
+            peers: Vec::new(),
+            tokens: HashMap::new(),
+            converged_on: None,
+        };
+
+        // Round 1: three nodes respond, seeding the closest-responded set. There's nothing to
+        // compare against yet, so this can't converge.
+        let round_one_ids: Vec<NodeID> = (0..3).map(|_| NodeID::random()).collect();
+        for id in &round_one_ids {
+            state.insert(node_at(id.clone()));
+            state.mark(id, QueryState::Responded);
+        }
+        assert!(!run_round(&mut state));
+
+        // Round 2: one of round 1's nodes times out and a previously-unseen node answers --
+        // exactly the `mark(Failed)` / `insert` + `mark(Responded)` transitions a real round
+        // produces. The closest-responded set changes, so this still shouldn't converge.
+        state.mark(&round_one_ids[0], QueryState::Failed);
+        let discovered = NodeID::random();
+        state.insert(node_at(discovered.clone()));
+        state.mark(&discovered, QueryState::Responded);
+        assert!(!run_round(&mut state));
+
+        // Round 3: nothing new is discovered and no state changes -- the closest-responded set
+        // is identical to last round, so it converges now.
+        assert!(run_round(&mut state));
+    }
+}
@@ -0,0 +1,127 @@
+//! Background routing-table maintenance: aging node quality, pinging questionable nodes before
+//! evicting them (LRU-first when a bucket is full), and refreshing buckets that have gone quiet.
+
+use dht::Dht;
+use errors::{Error, ErrorKind};
+use proto::NodeID;
+
+use std::time::{Duration, Instant};
+
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+/// A node is considered good if it answered a query within this window.
+pub const GOOD_NODE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// How often a maintenance pass runs over the routing table.
+pub const DEFAULT_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a bucket can go without activity before it's refreshed with a `find_node` for a
+/// random ID in its range.
+pub const DEFAULT_BUCKET_REFRESH_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Knobs for `Dht::maintain_routing_table`.
+#[derive(Clone, Copy)]
+pub struct MaintenanceConfig {
+    /// How often a maintenance pass runs.
+    pub interval: Duration,
+    /// How long a node can go without responding before it's downgraded from good to
+    /// questionable, and eventually pinged to check on it.
+    pub good_node_timeout: Duration,
+    /// How long a bucket can go without activity before it's refreshed.
+    pub bucket_refresh_timeout: Duration,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        MaintenanceConfig {
+            interval: DEFAULT_MAINTENANCE_INTERVAL,
+            good_node_timeout: GOOD_NODE_TIMEOUT,
+            bucket_refresh_timeout: DEFAULT_BUCKET_REFRESH_TIMEOUT,
+        }
+    }
+}
+
+impl Dht {
+    /// Runs routing-table maintenance forever, on `config.interval`: pings questionable nodes to
+    /// check whether they're still alive, and refreshes buckets that have gone quiet.
+    pub fn maintain_routing_table(
+        &self,
+        config: MaintenanceConfig,
+    ) -> impl Future<Item = (), Error = Error> {
+        let dht = self.clone();
+
+        Interval::new(Instant::now() + config.interval, config.interval)
+            .map_err(|_| Error::from(ErrorKind::MaintenanceError))
+            .for_each(move |_| dht.run_maintenance_pass(config))
+    }
+
+    fn run_maintenance_pass(
+        &self,
+        config: MaintenanceConfig,
+    ) -> impl Future<Item = (), Error = Error> {
+        let ping_future = self.ping_questionable_nodes(config.good_node_timeout);
+        let refresh_future = self.refresh_stale_buckets(config.bucket_refresh_timeout);
+
+        ping_future.join(refresh_future).map(|_| ())
+    }
+
+    /// Pings every node the routing table considers questionable, updating its good/bad state
+    /// based on whether it answers. The routing table itself decides when that tips a node into
+    /// eviction.
+    fn ping_questionable_nodes(
+        &self,
+        good_node_timeout: Duration,
+    ) -> impl Future<Item = (), Error = Error> {
+        let questionable = self
+            .routing_table
+            .lock()
+            .map(|routing_table| routing_table.questionable_nodes(good_node_timeout))
+            .unwrap_or_else(|_| Vec::new());
+
+        let send_transport = self.send_transport.clone();
+        let routing_table_arc = self.routing_table.clone();
+        let self_id = self.id();
+
+        let pings = questionable.into_iter().map(move |node| {
+            let routing_table_arc = routing_table_arc.clone();
+            let id = node.id().clone();
+
+            send_transport
+                .ping(self_id.clone(), node.address())
+                .then(move |result| {
+                    let mut routing_table = routing_table_arc.lock()?;
+
+                    match result {
+                        Ok(_) => routing_table.mark_responded(&id),
+                        Err(_) => routing_table.mark_failed(&id),
+                    }
+
+                    Ok(())
+                })
+        });
+
+        future::join_all(pings).map(|_| ())
+    }
+
+    /// Refreshes every bucket that hasn't seen activity within `bucket_refresh_timeout` by
+    /// running a `find_node` lookup for a random ID within that bucket's range.
+    fn refresh_stale_buckets(
+        &self,
+        bucket_refresh_timeout: Duration,
+    ) -> impl Future<Item = (), Error = Error> {
+        let stale_ranges = self
+            .routing_table
+            .lock()
+            .map(|routing_table| routing_table.stale_bucket_ranges(bucket_refresh_timeout))
+            .unwrap_or_else(|_| Vec::new());
+
+        let dht = self.clone();
+
+        let refreshes = stale_ranges
+            .into_iter()
+            .map(move |range| dht.lookup(NodeID::random_within(range), false).map(|_| ()));
+
+        future::join_all(refreshes).map(|_| ())
+    }
+}
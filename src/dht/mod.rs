@@ -1,3 +1,4 @@
+use addr::AsV4Address;
 use errors::{Error, Result};
 
 use proto::NodeID;
@@ -5,17 +6,28 @@ use routing::{Node, RoutingTable};
 use transport::{PortType, RecvTransport, SendTransport};
 
 use std::collections::HashMap;
-use std::net::{SocketAddr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::{Arc, Mutex};
 
+use rand;
+use tokio;
 use tokio::prelude::*;
 
+mod api;
 mod handler;
+mod lookup;
+mod maintenance;
+mod persistence;
+
+pub use dht::maintenance::MaintenanceConfig;
 
 /// BitTorrent DHT node
 #[derive(Clone)]
 pub struct Dht {
-    id: NodeID,
+    id: Arc<Mutex<NodeID>>,
+    /// Our external IPv4 address, once we've learned it from a peer's response (BEP 42). Once
+    /// set, it isn't replaced, to avoid a malicious peer bouncing us between IDs.
+    external_ip: Arc<Mutex<Option<Ipv4Addr>>>,
     torrents: Arc<Mutex<HashMap<NodeID, Vec<SocketAddrV4>>>>,
     send_transport: Arc<SendTransport>,
     routing_table: Arc<Mutex<RoutingTable>>,
@@ -24,23 +36,89 @@ pub struct Dht {
 
 impl Dht {
     /// Start handling inbound messages from other peers in the network. Continues to handle while
-    /// the future is polled.
-    pub fn start(bind_addr: SocketAddr) -> Result<(Dht, impl Future<Item = (), Error = Error>)> {
+    /// the future is polled. Routing-table maintenance runs on `MaintenanceConfig::default()`;
+    /// use `start_with_config` to pick different timers.
+    pub fn start(bind_addr: SocketAddr) -> Result<(Dht, impl Future<Item = (), Error = ()>)> {
+        Dht::start_with_config(bind_addr, MaintenanceConfig::default())
+    }
+
+    /// Like `start`, but with explicit control over the routing-table maintenance timers.
+    pub fn start_with_config(
+        bind_addr: SocketAddr,
+        maintenance_config: MaintenanceConfig,
+    ) -> Result<(Dht, impl Future<Item = (), Error = ()>)> {
         let transport = RecvTransport::new(bind_addr)?;
         let (send_transport, request_stream) = transport.serve();
 
         let id = NodeID::random();
-        let torrents = Arc::new(Mutex::new(HashMap::new()));
-        let routing_table = Arc::new(Mutex::new(RoutingTable::new(id.clone())));
+        let routing_table = RoutingTable::new(id.clone());
 
-        let dht = Dht {
-            id,
-            torrents,
+        let dht = Dht::from_parts(id, None, routing_table, HashMap::new(), send_transport);
+
+        let requests_future = dht.handle_requests(request_stream);
+        let maintenance_future = dht
+            .maintain_routing_table(maintenance_config)
+            .map_err(|err| eprintln!("Error During Routing Table Maintenance: {}", err));
+
+        // Maintenance runs as its own task rather than being `join`ed into the future callers
+        // are expected to drive forever: `join` completes (and stops driving the other side) the
+        // moment either future errors, so a single poisoned-lock maintenance pass would have
+        // silently ended inbound request handling along with it. `lazy` defers the `tokio::spawn`
+        // call until this future is actually polled, so it still runs inside the caller's runtime.
+        let combined = future::lazy(move || {
+            tokio::spawn(maintenance_future);
+            requests_future
+        });
+
+        Ok((dht.clone(), combined))
+    }
+
+    /// Assembles a `Dht` from its constituent pieces, used both by a fresh `start` and by
+    /// `load_from` when restoring a previous session. `external_ip` carries forward whatever a
+    /// restored session already knew, so `observe_external_ip` doesn't re-derive a fresh id
+    /// (with a new random low byte) out from under an id that's already valid for it.
+    fn from_parts(
+        id: NodeID,
+        external_ip: Option<Ipv4Addr>,
+        routing_table: RoutingTable,
+        torrents: HashMap<NodeID, Vec<SocketAddrV4>>,
+        send_transport: SendTransport,
+    ) -> Dht {
+        Dht {
+            id: Arc::new(Mutex::new(id)),
+            external_ip: Arc::new(Mutex::new(external_ip)),
+            torrents: Arc::new(Mutex::new(torrents)),
             send_transport: Arc::new(send_transport),
-            routing_table,
-        };
+            routing_table: Arc::new(Mutex::new(routing_table)),
+        }
+    }
+
+    /// Our current node ID.
+    fn id(&self) -> NodeID {
+        self.id.lock().expect("id lock poisoned").clone()
+    }
+
+    /// Records a peer's view of our external address (BEP 42) and, the first time we learn it,
+    /// re-derives our node ID from it so we comply with the security extension most mainline
+    /// nodes enforce. The routing table is re-keyed to the new id so its buckets stay correct
+    /// for the id they're now indexed by.
+    ///
+    /// Called with the address reported in a response's `ip` field by `bootstrap_routing_table`
+    /// and by the iterative lookup.
+    pub fn observe_external_ip(&self, addr: Ipv4Addr) -> Result<()> {
+        let mut external_ip = self.external_ip.lock()?;
+
+        if external_ip.is_some() {
+            return Ok(());
+        }
 
-        Ok((dht.clone(), dht.handle_requests(request_stream)))
+        *external_ip = Some(addr);
+
+        let new_id = NodeID::from_ip(addr, rand::random());
+        *self.id.lock()? = new_id.clone();
+        self.routing_table.lock()?.rekey(new_id);
+
+        Ok(())
     }
 
     /// Bootstraps the routing table by finding nodes near our node id and adding them to the
@@ -51,14 +129,28 @@ impl Dht {
     ) -> impl Future<Item = (), Error = Error> {
         let send_transport = self.send_transport.clone();
         let routing_table_arc = self.routing_table.clone();
-        let id = self.id.clone();
+        let id = self.id();
+        let dht_for_ip = self.clone();
 
         let bootstrap_futures = addrs.into_iter().map(move |addr| {
             let local_routing_table = routing_table_arc.clone();
+            let dht_for_ip = dht_for_ip.clone();
 
             send_transport
                 .ping(id.clone(), addr.clone().into())
-                .and_then(move |id| {
+                .and_then(move |(id, external_addr)| {
+                    if let Some(external_addr) = external_addr {
+                        dht_for_ip.observe_external_ip(*external_addr.ip())?;
+                    }
+
+                    // Seed nodes answer on addresses we dialed ourselves, but the id they hand
+                    // back is still self-reported -- gate it through the same trust check as
+                    // every other source of nodes (record_request, persistence::load_from) so a
+                    // seed can't hand us an arbitrary id for the address it's answering on.
+                    if !is_node_id_trustworthy(&id, addr.ip()) {
+                        return Ok(());
+                    }
+
                     let mut node = Node::new(id, addr.clone().into());
                     node.mark_successful_request();
 
@@ -69,37 +161,106 @@ impl Dht {
                 })
         });
 
-        let bootstrap_future = future::join_all(bootstrap_futures).and_then(|_| Ok(()));
-
-        bootstrap_future
+        let self_id = self.id();
+        let dht = self.clone();
+        let lookup_routing_table = self.routing_table.clone();
 
-        // TODO:
-        // * Query Node for Self Until Some Amount of Nodes Have Been Successfully Added
+        future::join_all(bootstrap_futures)
+            // Seeds are in the routing table now; run a self-lookup so it actually fills out
+            // with the nodes closest to us rather than staying limited to the seeds.
+            .and_then(move |_| dht.lookup(self_id, false))
+            .and_then(move |result| {
+                let mut routing_table = lookup_routing_table.lock()?;
+                for mut node in result.nodes {
+                    let trusted = node.address().into_v4().ok()
+                        .map_or(false, |addr| is_node_id_trustworthy(node.id(), addr.ip()));
+                    if trusted {
+                        node.mark_successful_request();
+                        routing_table.add_node(node);
+                    }
+                }
+                Ok(())
+            })
     }
 
-    /// Gets a list of peers seeding `info_hash`.
+    /// Gets a list of peers seeding `info_hash`, querying the network if we don't already know
+    /// of any.
     pub fn get_peers(
         &self,
         info_hash: NodeID,
     ) -> impl Future<Item = Vec<SocketAddrV4>, Error = Error> {
-        // TODO:
-        // * Return From torrents Table if Exists
-        // * Fetch By Calling get_nodes otherwise
-        future::ok(Vec::new())
+        let cached: Result<Option<Vec<SocketAddrV4>>> = (|| {
+            let torrents = self.torrents.lock()?;
+            Ok(torrents.get(&info_hash).cloned())
+        })();
+
+        let torrents = self.torrents.clone();
+        let dht = self.clone();
+
+        future::result(cached).and_then(move |cached| {
+            if let Some(peers) = cached {
+                return future::Either::A(future::ok(peers));
+            }
+
+            future::Either::B(dht.lookup(info_hash.clone(), true).and_then(move |result| {
+                let peers: Vec<SocketAddrV4> = result
+                    .peers
+                    .into_iter()
+                    .map(SocketAddrV4::from)
+                    .collect();
+
+                if !peers.is_empty() {
+                    torrents
+                        .lock()?
+                        .insert(info_hash, peers.clone());
+                }
+
+                Ok(peers)
+            }))
+        })
     }
 
     /// Announces that we have information about an info_hash on `port`.
+    ///
+    /// Runs a `get_peers`-style lookup to find the nodes closest to `info_hash` and collect the
+    /// token each one hands back, then sends `announce_peer` with its matching token to every
+    /// one of them that handed back a token; nodes without one are skipped. Individual node failures are ignored, since it's enough for the
+    /// announcement to reach some of the closest nodes.
     pub fn announce(
         &self,
         info_hash: NodeID,
         port: PortType,
     ) -> impl Future<Item = (), Error = Error> {
-        // TODO:
-        // * Send Announce to all Peers With Tokens
-        future::ok(())
+        let send_transport = self.send_transport.clone();
+        let self_id = self.id();
+
+        self.lookup(info_hash.clone(), true).and_then(move |result| {
+            let tokens = result.tokens;
+
+            let announce_futures = result.nodes.into_iter().filter_map(move |node| {
+                let address = node.address();
+                let token = address.into_v4().ok().and_then(|addr| tokens.get(&addr).cloned())?;
+
+                Some(
+                    send_transport
+                        .announce_peer(self_id.clone(), info_hash.clone(), token, port, address)
+                        .then(|_| Ok(())),
+                )
+            });
+
+            future::join_all(announce_futures).map(|_| ())
+        })
     }
 }
 
+/// BEP 42: a node's ID must be derived from the IP address it's querying from, so nodes can't
+/// pick arbitrary IDs to cluster themselves around a target (a Sybil attack). Loopback and
+/// private addresses are exempt, since they're used for local testing and can't be verified
+/// against an externally-visible address anyway.
+pub(crate) fn is_node_id_trustworthy(id: &NodeID, from: &Ipv4Addr) -> bool {
+    from.is_loopback() || from.is_private() || id.is_valid_for_ip(*from)
+}
+
 #[cfg(test)]
 mod tests {
     use futures::Future;
@@ -124,6 +285,28 @@ mod tests {
             .collect()
     }
 
+    #[test]
+    fn node_id_from_ip_is_valid_for_that_ip() {
+        use std::net::Ipv4Addr;
+
+        let ip = Ipv4Addr::new(86, 75, 30, 9);
+        let id = ::proto::NodeID::from_ip(ip, 42);
+
+        assert!(id.is_valid_for_ip(ip));
+    }
+
+    #[test]
+    fn loopback_and_private_addresses_skip_id_validation() {
+        use std::net::Ipv4Addr;
+
+        use super::is_node_id_trustworthy;
+
+        let id = ::proto::NodeID::random();
+
+        assert!(is_node_id_trustworthy(&id, &Ipv4Addr::new(127, 0, 0, 1)));
+        assert!(is_node_id_trustworthy(&id, &Ipv4Addr::new(192, 168, 1, 1)));
+    }
+
     #[test]
     fn test_bootstrap() {
         let addr = "0.0.0.0:0".to_socket_addrs().unwrap().nth(0).unwrap();
@@ -0,0 +1,241 @@
+//! Snapshotting the routing table and torrent store to disk.
+
+use addr::AsV4Address;
+use dht::{is_node_id_trustworthy, Dht, MaintenanceConfig};
+use errors::{Error, ErrorKind, Result};
+use failure::ResultExt;
+use proto::NodeID;
+use routing::{Node, RoutingTable};
+use transport::RecvTransport;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use bincode;
+use bzip2::read::BzDecoder;
+use bzip2::write::BzEncoder;
+use bzip2::Compression;
+use tokio;
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+/// Everything needed to pick a crawl back up: the nodes we knew about and the torrents we'd
+/// heard announced. Only each node's id and address are saved; its quality and last-seen state
+/// are not, and `load_from` marks every restored node as having just answered a request.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    id: NodeID,
+    /// The external address `id` was derived from (BEP 42), if any was known. Carried across
+    /// restarts so a restored `Dht` doesn't immediately re-derive a different id the next time
+    /// it observes the same address.
+    external_ip: Option<Ipv4Addr>,
+    nodes: Vec<SerializedNode>,
+    torrents: HashMap<NodeID, Vec<SocketAddrV4>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerializedNode {
+    id: NodeID,
+    address: SocketAddrV4,
+}
+
+impl Dht {
+    /// Serializes the routing table and torrent store to `path`, bzip2-compressed.
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let nodes = self
+            .routing_table
+            .lock()?
+            .nodes()
+            .into_iter()
+            .filter_map(|node| {
+                let address = node.address().into_v4().ok()?;
+
+                Some(SerializedNode {
+                    id: node.id().clone(),
+                    address,
+                })
+            }).collect();
+
+        let torrents = self.torrents.lock()?.clone();
+        let external_ip = *self.external_ip.lock()?;
+
+        let snapshot = Snapshot {
+            id: self.id(),
+            external_ip,
+            nodes,
+            torrents,
+        };
+
+        let file = File::create(path).context(ErrorKind::PersistenceError)?;
+        let mut writer = BzEncoder::new(BufWriter::new(file), Compression::Best);
+
+        bincode::serialize_into(&mut writer, &snapshot).context(ErrorKind::PersistenceError)?;
+
+        Ok(())
+    }
+
+    /// Like `load_from`, but with explicit control over the routing-table maintenance timers.
+    pub fn load_from_with_config<P: AsRef<Path>>(
+        bind_addr: SocketAddr,
+        path: P,
+        maintenance_config: MaintenanceConfig,
+    ) -> Result<(Dht, impl Future<Item = (), Error = ()>)> {
+        let file = File::open(path).context(ErrorKind::PersistenceError)?;
+        let reader = BzDecoder::new(BufReader::new(file));
+
+        let snapshot: Snapshot =
+            bincode::deserialize_from(reader).context(ErrorKind::PersistenceError)?;
+
+        let mut routing_table = RoutingTable::new(snapshot.id.clone());
+
+        for serialized in snapshot.nodes {
+            if !is_node_id_trustworthy(&serialized.id, serialized.address.ip()) {
+                continue;
+            }
+
+            let mut node = Node::new(serialized.id, serialized.address.into());
+            node.mark_successful_request();
+
+            routing_table.add_node(node);
+        }
+
+        let transport = RecvTransport::new(bind_addr)?;
+        let (send_transport, request_stream) = transport.serve();
+
+        let dht = Dht::from_parts(
+            snapshot.id,
+            snapshot.external_ip,
+            routing_table,
+            snapshot.torrents,
+            send_transport,
+        );
+
+        let requests_future = dht.handle_requests(request_stream);
+        let maintenance_future = dht
+            .maintain_routing_table(maintenance_config)
+            .map_err(|err| eprintln!("Error During Routing Table Maintenance: {}", err));
+
+        // Same reasoning as start_with_config: maintenance runs as its own task, deferred via
+        // `lazy` so the `tokio::spawn` call happens once this future is actually polled inside
+        // the caller's runtime, not at construction time.
+        let combined = future::lazy(move || {
+            tokio::spawn(maintenance_future);
+            requests_future
+        });
+
+        Ok((dht.clone(), combined))
+    }
+
+    /// Rebuilds a `Dht` from a snapshot written by `save_to`, binding a fresh socket at
+    /// `bind_addr`. Each node is re-validated against its address (BEP 42) before being
+    /// reinserted, so a snapshot can't be used to smuggle in spoofed nodes. Routing-table
+    /// maintenance runs on `MaintenanceConfig::default()`; use `load_from_with_config` to pick
+    /// different timers.
+    pub fn load_from<P: AsRef<Path>>(
+        bind_addr: SocketAddr,
+        path: P,
+    ) -> Result<(Dht, impl Future<Item = (), Error = ()>)> {
+        Dht::load_from_with_config(bind_addr, path, MaintenanceConfig::default())
+    }
+
+    /// Returns a future that saves a snapshot to `path` on a fixed interval, for callers that
+    /// want crash resilience without manually scheduling `save_to`. Save errors are logged and
+    /// otherwise ignored so a transient disk failure doesn't bring the crawl down.
+    pub fn save_periodically<P: AsRef<Path> + Send + 'static>(
+        &self,
+        path: P,
+        interval: Duration,
+    ) -> impl Future<Item = (), Error = Error> {
+        let dht = self.clone();
+
+        Interval::new(Instant::now() + interval, interval)
+            .map_err(|_| Error::from(ErrorKind::PersistenceError))
+            .for_each(move |_| {
+                if let Err(err) = dht.save_to(&path) {
+                    eprintln!("Error While Saving DHT Snapshot: {}", err);
+                }
+
+                Ok(())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Dht;
+
+    use std::net::ToSocketAddrs;
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let addr = "0.0.0.0:0".to_socket_addrs().unwrap().nth(0).unwrap();
+        let (dht, _dht_future) = Dht::start(addr).unwrap();
+
+        let info_hash = NodeID::random();
+        let peers = vec!["127.0.0.1:6881".parse().unwrap()];
+        dht.torrents
+            .lock()
+            .unwrap()
+            .insert(info_hash.clone(), peers.clone());
+
+        let path = ::std::env::temp_dir().join("dht-crawler-test-snapshot.bin");
+        dht.save_to(&path).unwrap();
+
+        let (restored, _restored_future) = Dht::load_from(addr, &path).unwrap();
+
+        assert_eq!(restored.id(), dht.id());
+        assert_eq!(
+            restored.torrents.lock().unwrap().get(&info_hash),
+            Some(&peers)
+        );
+
+        ::std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn load_from_drops_nodes_with_untrustworthy_ids() {
+        let addr = "0.0.0.0:0".to_socket_addrs().unwrap().nth(0).unwrap();
+        let (dht, _dht_future) = Dht::start(addr).unwrap();
+
+        // Neither address is loopback/private, so both ids are actually checked against
+        // is_node_id_trustworthy instead of being exempted.
+        let trusted_ip = Ipv4Addr::new(8, 8, 8, 8);
+        let trusted_addr = SocketAddrV4::new(trusted_ip, 6881);
+        let trusted_id = NodeID::from_ip(trusted_ip, 7);
+
+        let untrusted_ip = Ipv4Addr::new(8, 8, 4, 4);
+        let untrusted_addr = SocketAddrV4::new(untrusted_ip, 6881);
+        let untrusted_id = NodeID::random();
+        assert!(!untrusted_id.is_valid_for_ip(untrusted_ip));
+
+        {
+            let mut routing_table = dht.routing_table.lock().unwrap();
+            routing_table.add_node(Node::new(trusted_id.clone(), trusted_addr.into()));
+            routing_table.add_node(Node::new(untrusted_id.clone(), untrusted_addr.into()));
+        }
+
+        let path = ::std::env::temp_dir().join("dht-crawler-test-snapshot-trust.bin");
+        dht.save_to(&path).unwrap();
+
+        let (restored, _restored_future) = Dht::load_from(addr, &path).unwrap();
+
+        let restored_ids: Vec<NodeID> = restored
+            .routing_table
+            .lock()
+            .unwrap()
+            .nodes()
+            .into_iter()
+            .map(|node| node.id().clone())
+            .collect();
+
+        assert!(restored_ids.contains(&trusted_id));
+        assert!(!restored_ids.contains(&untrusted_id));
+
+        ::std::fs::remove_file(&path).ok();
+    }
+}